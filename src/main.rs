@@ -2,55 +2,113 @@ mod profile;
 
 use bytes::Bytes;
 use flate2::read::GzDecoder;
-use profile::{Location, Profile};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use profile::{Function, Label, Location, Mapping, Profile, Sample, ValueType};
 use prost::Message;
 use regex::Regex;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{self, BufReader, Read},
+    io::{self, BufReader, Read, Write},
     path::PathBuf,
 };
 use structopt::StructOpt;
 
 /// pprof_blame - A tool for analyzing pprof profiles with call stack relationships
 ///
-/// This utility processes pprof profile files and filters samples based on the
-/// relationships between functions in the call stack. It uses three patterns:
-///
-/// - Blame: The primary function pattern to match in the stack
-/// - Parent: Function pattern that must appear as an ancestor of the blame function
-/// - Exclude: Function pattern that, if present, will exclude the sample
-///
-/// For each sample, it checks whether:
-/// 1. A function matching the blame pattern exists
-/// 2. A function matching the parent pattern exists (if specified)
-/// 3. The blame function is called by the parent function
-/// 4. No function matching the exclude pattern is present
-///
 /// Usage:
-///   pprof_blame --file profile.pb.gz --blame "pattern" [--parent "pattern"] [--exclude "pattern"]
-///
-/// Output:
-///   - With parent: matched samples as percentage of parent samples
-///   - Without parent: matched samples as percentage of total samples
+///   pprof_blame blame --file profile.pb.gz --blame "pattern" [--parent "pattern"] [--exclude "pattern"]
+///   pprof_blame top --file profile.pb.gz [--limit N] [--cumulative]
 #[derive(StructOpt, Debug)]
-struct Opt {
-    /// Path to the profile file (.pb.gz)
-    #[structopt(long)]
-    file: PathBuf,
+#[structopt(name = "pprof_blame")]
+enum Opt {
+    /// Filters samples based on the relationships between functions in the
+    /// call stack. It uses three patterns:
+    ///
+    /// - Blame: The primary function pattern to match in the stack
+    /// - Parent: Function pattern that must appear as an ancestor of the blame function
+    /// - Exclude: Function pattern that, if present, will exclude the sample
+    ///
+    /// For each sample, it checks whether:
+    /// 1. A function matching the blame pattern exists
+    /// 2. A function matching the parent pattern exists (if specified)
+    /// 3. The blame function is called by the parent function
+    /// 4. No function matching the exclude pattern is present
+    ///
+    /// Output:
+    ///   - With parent: matched samples as percentage of parent samples
+    ///   - Without parent: matched samples as percentage of total samples
+    Blame {
+        /// Path to the profile file (.pb.gz)
+        #[structopt(long)]
+        file: PathBuf,
 
-    /// Regex pattern for functions to blame
-    #[structopt(long)]
-    blame: String,
+        /// Regex pattern for functions to blame
+        #[structopt(long)]
+        blame: String,
 
-    /// Optional regex pattern for parent functions
-    #[structopt(long)]
-    parent: Option<String>,
+        /// Optional regex pattern for parent functions
+        #[structopt(long)]
+        parent: Option<String>,
 
-    /// Optional regex pattern for functions to exclude
-    #[structopt(long)]
-    exclude: Option<String>,
+        /// Optional regex pattern for functions to exclude
+        #[structopt(long)]
+        exclude: Option<String>,
+
+        /// Which sample value column to analyze, matched against the decoded
+        /// `type` (e.g. "cpu") or `type/unit` (e.g. "cpu/nanoseconds") of one of
+        /// the profile's `sample_type` entries. Defaults to the profile's
+        /// `default_sample_type`, or the first column if that isn't set.
+        #[structopt(long = "sample-type")]
+        sample_type: Option<String>,
+
+        /// Write a new pprof profile (.pb.gz) containing only the blamed
+        /// samples, with locations/functions/mappings/strings garbage-collected
+        /// and renumbered so the result can be fed back into pprof/flamegraph tools.
+        #[structopt(long)]
+        output: Option<PathBuf>,
+
+        /// Print collapsed-stack ("folded") output instead of the aggregate
+        /// summary, for piping into flamegraph.pl/inferno.
+        #[structopt(long)]
+        folded: bool,
+
+        /// Path to a baseline profile (.pb.gz). When set, reports per-blamed-frame
+        /// deltas between this run and the baseline instead of the aggregate summary.
+        #[structopt(long)]
+        baseline: Option<PathBuf>,
+
+        /// Restrict analysis to samples with a matching label, given as `key=value`
+        /// (repeatable, all must match). Numeric labels are compared against `value`
+        /// parsed as an integer. Useful for filtering to a single thread, goroutine,
+        /// or request tag.
+        #[structopt(long = "label")]
+        labels: Vec<String>,
+    },
+
+    /// Prints the N heaviest functions across the whole profile, ignoring
+    /// blame/parent/exclude - a quick overview pass before drilling in with
+    /// `blame`.
+    Top {
+        /// Path to the profile file (.pb.gz)
+        #[structopt(long)]
+        file: PathBuf,
+
+        /// Which sample value column to analyze (see `blame --sample-type`)
+        #[structopt(long = "sample-type")]
+        sample_type: Option<String>,
+
+        /// Number of functions to print
+        #[structopt(long, default_value = "10")]
+        limit: usize,
+
+        /// Aggregate each frame's value over every sample in which it
+        /// appears (cumulative/inclusive), instead of only the leaf frame
+        /// actually executing at sample time (self time).
+        #[structopt(long)]
+        cumulative: bool,
+    },
 }
 
 /// Result of analyzing a profile
@@ -67,6 +125,9 @@ struct AnalysisResults {
     blamed_frames: HashMap<String, (usize, i64)>,
     parent_frames: HashMap<String, (usize, i64)>,
     excluded_frames: HashMap<String, (usize, i64)>,
+    /// Full samples classified as "blamed" (matched, not excluded), kept
+    /// around for output modes that need more than the aggregate counts.
+    blamed_samples_raw: Vec<Sample>,
 }
 
 impl AnalysisResults {
@@ -83,6 +144,7 @@ impl AnalysisResults {
             blamed_frames: HashMap::new(),
             parent_frames: HashMap::new(),
             excluded_frames: HashMap::new(),
+            blamed_samples_raw: Vec::new(),
         }
     }
 
@@ -96,62 +158,160 @@ impl AnalysisResults {
         }
     }
 
-    fn print_summary(&self, has_parent: bool) {
-        let blamed_value_ms = self.blamed_value / 1_000_000;
-        let parent_value_ms = self.parent_value / 1_000_000;
-        let total_value_ms = self.total_value / 1_000_000;
-        let excluded_value_ms = self.excluded_value / 1_000_000;
+    fn print_summary(&self, has_parent: bool, sample_type: &SampleTypeInfo) {
+        println!(
+            "Analyzing sample type: {} ({})\n",
+            sample_type.name, sample_type.unit
+        );
 
         if has_parent {
             println!(
-                "{} blamed samples ({} ms) over {} parent samples ({} ms) ({:.2}%).",
+                "{} blamed samples ({}) over {} parent samples ({}) ({:.2}%).",
                 self.blamed_samples,
-                blamed_value_ms,
+                sample_type.format_value(self.blamed_value),
                 self.parent_samples,
-                parent_value_ms,
+                sample_type.format_value(self.parent_value),
                 self.percentage()
             );
         } else {
             println!(
-                "{} blamed samples ({} ms) over {} total samples ({} ms) ({:.2}%).",
+                "{} blamed samples ({}) over {} total samples ({}) ({:.2}%).",
                 self.blamed_samples,
-                blamed_value_ms,
+                sample_type.format_value(self.blamed_value),
                 self.total_samples,
-                total_value_ms,
+                sample_type.format_value(self.total_value),
                 self.percentage()
             );
         }
 
         if self.excluded_samples > 0 {
             println!(
-                "{} samples ({} ms) were excluded.",
-                self.excluded_samples, excluded_value_ms
+                "{} samples ({}) were excluded.",
+                self.excluded_samples,
+                sample_type.format_value(self.excluded_value)
             );
         }
 
         if !self.blamed_frames.is_empty() {
             println!("\nBlamed Frames:");
             for (method, (count, value)) in &self.blamed_frames {
-                println!("{}: {} samples, {} ms", method, count, value / 1_000_000);
+                println!(
+                    "{}: {} samples, {}",
+                    method,
+                    count,
+                    sample_type.format_value(*value)
+                );
             }
         }
 
         if has_parent && !self.parent_frames.is_empty() {
             println!("\nParent Frames:");
             for (method, (count, value)) in &self.parent_frames {
-                println!("{}: {} samples, {} ms", method, count, value / 1_000_000);
+                println!(
+                    "{}: {} samples, {}",
+                    method,
+                    count,
+                    sample_type.format_value(*value)
+                );
             }
         }
 
         if !self.excluded_frames.is_empty() {
             println!("\nExcluded Frames:");
             for (method, (count, value)) in &self.excluded_frames {
-                println!("{}: {} samples, {} ms", method, count, value / 1_000_000);
+                println!(
+                    "{}: {} samples, {}",
+                    method,
+                    count,
+                    sample_type.format_value(*value)
+                );
             }
         }
     }
 }
 
+/// Identifies which `sample.value` column is being analyzed
+struct SampleTypeInfo {
+    name: String,
+    unit: String,
+}
+
+impl SampleTypeInfo {
+    /// True when values in this column are durations in nanoseconds, the
+    /// only case where converting to milliseconds for display makes sense.
+    fn is_nanoseconds(&self) -> bool {
+        self.unit == "nanoseconds"
+    }
+
+    /// Renders a raw sample value using this column's unit, converting
+    /// nanoseconds to milliseconds for readability.
+    fn format_value(&self, value: i64) -> String {
+        if self.is_nanoseconds() {
+            format!("{} ms", value / 1_000_000)
+        } else {
+            format!("{} {}", value, self.unit)
+        }
+    }
+}
+
+/// Resolves which `sample.value` column to analyze.
+///
+/// If `requested` is given (as "type" or "type/unit"), it is matched against
+/// `profile.sample_type`. Otherwise falls back to `profile.default_sample_type`
+/// if set, and finally to the first column.
+fn resolve_sample_type(
+    profile: &Profile,
+    string_table: &StringTable,
+    requested: Option<&str>,
+) -> Result<(usize, SampleTypeInfo), Box<dyn std::error::Error>> {
+    if let Some(requested) = requested {
+        let (want_type, want_unit) = match requested.split_once('/') {
+            Some((t, u)) => (t, Some(u)),
+            None => (requested, None),
+        };
+
+        let idx = profile.sample_type.iter().position(|vt| {
+            let ty = string_table.get(vt.r#type);
+            let unit = string_table.get(vt.unit);
+            ty == want_type && want_unit.is_none_or(|u| u == unit)
+        });
+
+        return match idx {
+            Some(idx) => {
+                let vt = &profile.sample_type[idx];
+                Ok((
+                    idx,
+                    SampleTypeInfo {
+                        name: string_table.get(vt.r#type).to_string(),
+                        unit: string_table.get(vt.unit).to_string(),
+                    },
+                ))
+            }
+            None => Err(format!("no sample type matching '{}'", requested).into()),
+        };
+    }
+
+    let default_idx = profile
+        .sample_type
+        .iter()
+        .position(|vt| string_table.get(vt.r#type) == string_table.get(profile.default_sample_type))
+        .filter(|_| profile.default_sample_type != 0);
+
+    let idx = default_idx.unwrap_or(0);
+    let info = match profile.sample_type.get(idx) {
+        Some(vt) => SampleTypeInfo {
+            name: string_table.get(vt.r#type).to_string(),
+            unit: string_table.get(vt.unit).to_string(),
+        },
+        None => SampleTypeInfo {
+            name: "unknown".to_string(),
+            unit: "unknown".to_string(),
+        },
+    };
+
+    Ok((idx, info))
+}
+
 /// A wrapper around the profile string table for safer access
 struct StringTable<'a> {
     table: &'a [String],
@@ -170,6 +330,41 @@ impl<'a> StringTable<'a> {
     }
 }
 
+/// Parses `--label key=value` options into `(key, value)` pairs.
+fn parse_label_filters(raw: &[String]) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    raw.iter()
+        .map(|entry| {
+            entry
+                .split_once('=')
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .ok_or_else(|| format!("invalid --label '{}', expected key=value", entry).into())
+        })
+        .collect()
+}
+
+/// Whether a sample's decoded labels satisfy every `(key, value)` filter.
+/// String-valued labels are compared as decoded strings; numeric labels are
+/// compared against `value` parsed as an integer.
+fn sample_matches_labels(
+    sample: &profile::Sample,
+    filters: &[(String, String)],
+    string_table: &StringTable,
+) -> bool {
+    filters.iter().all(|(key, value)| {
+        sample.label.iter().any(|label| {
+            if string_table.get(label.key) != key {
+                return false;
+            }
+
+            if label.str != 0 {
+                string_table.get(label.str) == value
+            } else {
+                value.parse::<i64>().map(|v| v == label.num).unwrap_or(false)
+            }
+        })
+    })
+}
+
 /// Loads and decodes a profile from a file
 fn load_profile(path: &PathBuf) -> io::Result<Profile> {
     let file = File::open(path)?;
@@ -180,6 +375,223 @@ fn load_profile(path: &PathBuf) -> io::Result<Profile> {
     Profile::decode(Bytes::from(buf)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
+/// Builds a new profile containing only `kept_samples`, with the
+/// `location`, `function`, `mapping`, and `string_table` entries they
+/// reference garbage-collected and renumbered so the result is a
+/// self-contained, valid pprof profile.
+fn build_filtered_profile(original: &Profile, kept_samples: &[Sample]) -> Profile {
+    let location_by_id: HashMap<u64, &Location> =
+        original.location.iter().map(|l| (l.id, l)).collect();
+    let function_by_id: HashMap<u64, &Function> =
+        original.function.iter().map(|f| (f.id, f)).collect();
+    let mapping_by_id: HashMap<u64, &Mapping> =
+        original.mapping.iter().map(|m| (m.id, m)).collect();
+
+    // Walk the kept samples to find which locations (and, transitively,
+    // functions and mappings) are still reachable.
+    let mut kept_location_ids: Vec<u64> = Vec::new();
+    for sample in kept_samples {
+        for &loc_id in &sample.location_id {
+            if !kept_location_ids.contains(&loc_id) {
+                kept_location_ids.push(loc_id);
+            }
+        }
+    }
+
+    let mut kept_function_ids: Vec<u64> = Vec::new();
+    for &loc_id in &kept_location_ids {
+        if let Some(loc) = location_by_id.get(&loc_id) {
+            for line in &loc.line {
+                if !kept_function_ids.contains(&line.function_id) {
+                    kept_function_ids.push(line.function_id);
+                }
+            }
+        }
+    }
+
+    let mut kept_mapping_ids: Vec<u64> = Vec::new();
+    for &loc_id in &kept_location_ids {
+        if let Some(loc) = location_by_id.get(&loc_id) {
+            if loc.mapping_id != 0 && !kept_mapping_ids.contains(&loc.mapping_id) {
+                kept_mapping_ids.push(loc.mapping_id);
+            }
+        }
+    }
+
+    // Renumber ids starting at 1 (0 is reserved to mean "none" in pprof).
+    let location_id_map: HashMap<u64, u64> = kept_location_ids
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| (id, i as u64 + 1))
+        .collect();
+    let function_id_map: HashMap<u64, u64> = kept_function_ids
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| (id, i as u64 + 1))
+        .collect();
+    let mapping_id_map: HashMap<u64, u64> = kept_mapping_ids
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| (id, i as u64 + 1))
+        .collect();
+
+    // Build the new string table out of every string index still reachable
+    // from the entries we're keeping, preserving index 0 as the empty string.
+    let mut new_string_table: Vec<String> = vec![String::new()];
+    let mut string_id_map: HashMap<i64, i64> = HashMap::new();
+    string_id_map.insert(0, 0);
+
+    let intern = |old_index: i64, table: &mut Vec<String>, map: &mut HashMap<i64, i64>| {
+        if let Some(&new_index) = map.get(&old_index) {
+            return new_index;
+        }
+        let s = original
+            .string_table
+            .get(old_index as usize)
+            .cloned()
+            .unwrap_or_default();
+        let new_index = table.len() as i64;
+        table.push(s);
+        map.insert(old_index, new_index);
+        new_index
+    };
+
+    let new_functions: Vec<Function> = kept_function_ids
+        .iter()
+        .filter_map(|id| function_by_id.get(id))
+        .map(|f| Function {
+            id: function_id_map[&f.id],
+            name: intern(f.name, &mut new_string_table, &mut string_id_map),
+            system_name: intern(f.system_name, &mut new_string_table, &mut string_id_map),
+            filename: intern(f.filename, &mut new_string_table, &mut string_id_map),
+            start_line: f.start_line,
+        })
+        .collect();
+
+    let new_mappings: Vec<Mapping> = kept_mapping_ids
+        .iter()
+        .filter_map(|id| mapping_by_id.get(id))
+        .map(|m| Mapping {
+            id: mapping_id_map[&m.id],
+            memory_start: m.memory_start,
+            memory_limit: m.memory_limit,
+            file_offset: m.file_offset,
+            filename: intern(m.filename, &mut new_string_table, &mut string_id_map),
+            build_id: intern(m.build_id, &mut new_string_table, &mut string_id_map),
+            has_functions: m.has_functions,
+            has_filenames: m.has_filenames,
+            has_line_numbers: m.has_line_numbers,
+            has_inline_frames: m.has_inline_frames,
+        })
+        .collect();
+
+    let new_locations: Vec<Location> = kept_location_ids
+        .iter()
+        .filter_map(|id| location_by_id.get(id))
+        .map(|l| Location {
+            id: location_id_map[&l.id],
+            mapping_id: mapping_id_map.get(&l.mapping_id).copied().unwrap_or(0),
+            address: l.address,
+            line: l
+                .line
+                .iter()
+                .map(|line| profile::Line {
+                    function_id: function_id_map
+                        .get(&line.function_id)
+                        .copied()
+                        .unwrap_or(0),
+                    line: line.line,
+                })
+                .collect(),
+            is_folded: l.is_folded,
+        })
+        .collect();
+
+    let new_samples: Vec<Sample> = kept_samples
+        .iter()
+        .map(|s| Sample {
+            location_id: s
+                .location_id
+                .iter()
+                .filter_map(|id| location_id_map.get(id).copied())
+                .collect(),
+            value: s.value.clone(),
+            label: s
+                .label
+                .iter()
+                .map(|label| Label {
+                    key: intern(label.key, &mut new_string_table, &mut string_id_map),
+                    str: intern(label.str, &mut new_string_table, &mut string_id_map),
+                    num: label.num,
+                    num_unit: intern(label.num_unit, &mut new_string_table, &mut string_id_map),
+                })
+                .collect(),
+        })
+        .collect();
+
+    let new_sample_type: Vec<ValueType> = original
+        .sample_type
+        .iter()
+        .map(|vt| ValueType {
+            r#type: intern(vt.r#type, &mut new_string_table, &mut string_id_map),
+            unit: intern(vt.unit, &mut new_string_table, &mut string_id_map),
+        })
+        .collect();
+
+    let default_sample_type = intern(
+        original.default_sample_type,
+        &mut new_string_table,
+        &mut string_id_map,
+    );
+    let drop_frames = intern(original.drop_frames, &mut new_string_table, &mut string_id_map);
+    let keep_frames = intern(original.keep_frames, &mut new_string_table, &mut string_id_map);
+    let comment = intern(original.comment, &mut new_string_table, &mut string_id_map);
+
+    Profile {
+        sample_type: new_sample_type,
+        sample: new_samples,
+        mapping: new_mappings,
+        location: new_locations,
+        function: new_functions,
+        string_table: new_string_table,
+        drop_frames,
+        keep_frames,
+        time_nanos: original.time_nanos,
+        duration_nanos: original.duration_nanos,
+        period_type: original.period_type.clone(),
+        period: original.period,
+        comment,
+        default_sample_type,
+    }
+}
+
+/// Gzip-encodes and writes a profile to `path`.
+fn write_filtered_profile(path: &PathBuf, profile: &Profile) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Builds the `function_id -> name` and `location_id -> Location` lookup
+/// maps shared by every pass that walks sample stacks.
+fn build_frame_maps<'a>(
+    profile: &'a Profile,
+    string_table: &StringTable<'a>,
+) -> (HashMap<u64, &'a str>, HashMap<u64, &'a Location>) {
+    let function_map: HashMap<u64, &str> = profile
+        .function
+        .iter()
+        .map(|f| (f.id, string_table.get(f.name)))
+        .collect();
+
+    let location_map: HashMap<u64, &Location> =
+        profile.location.iter().map(|l| (l.id, l)).collect();
+
+    (function_map, location_map)
+}
+
 /// Extracts function names from a sample's stack trace
 fn extract_stack<'a>(
     sample: &'a profile::Sample,
@@ -199,30 +611,59 @@ fn extract_stack<'a>(
         .collect()
 }
 
+/// Result of searching a (possibly parent-bounded) stack for the blame frame
+struct BlameMatch {
+    blame_idx: usize,
+    excluded: bool,
+}
+
+/// Looks for the blame pattern within `search_range`, and, if found, checks
+/// whether a frame before it matches the exclude pattern. Shared by every
+/// output mode so the blame/exclude predicate stays in exactly one place.
+fn find_blame_match(
+    search_range: &[&str],
+    blame_re: &Regex,
+    exclude_re: Option<&Regex>,
+) -> Option<BlameMatch> {
+    let blame_idx = search_range.iter().position(|&name| blame_re.is_match(name))?;
+
+    let excluded = exclude_re
+        .map(|pattern| {
+            search_range[..blame_idx]
+                .iter()
+                .any(|&name| pattern.is_match(name))
+        })
+        .unwrap_or(false);
+
+    Some(BlameMatch {
+        blame_idx,
+        excluded,
+    })
+}
+
 /// Analyzes a profile with given filter patterns
 fn analyze_profile(
     profile: &Profile,
     blame_re: &Regex,
     parent_re: Option<&Regex>,
     exclude_re: Option<&Regex>,
+    value_index: usize,
+    label_filters: &[(String, String)],
 ) -> AnalysisResults {
     // Create a more efficient string table accessor
     let string_table = StringTable::new(&profile.string_table);
 
     // Build maps for faster lookups
-    let function_map: HashMap<u64, &str> = profile
-        .function
-        .iter()
-        .map(|f| (f.id, string_table.get(f.name)))
-        .collect();
-
-    let location_map: HashMap<u64, &Location> =
-        profile.location.iter().map(|l| (l.id, l)).collect();
+    let (function_map, location_map) = build_frame_maps(profile, &string_table);
 
     let mut results = AnalysisResults::new();
 
     // Process each sample
     for sample in &profile.sample {
+        if !label_filters.is_empty() && !sample_matches_labels(sample, label_filters, &string_table) {
+            continue;
+        }
+
         let stack = extract_stack(sample, &location_map, &function_map);
 
         if stack.is_empty() {
@@ -230,7 +671,7 @@ fn analyze_profile(
         }
 
         results.total_samples += 1;
-        let value = sample.value.first().copied().unwrap_or(0);
+        let value = sample.value.get(value_index).copied().unwrap_or(0);
         results.total_value += value;
 
         // First, check for parent frame if a parent pattern is specified
@@ -264,24 +705,12 @@ fn analyze_profile(
             &stack[..]
         };
 
-        // Look for blame frame in the determined search range
-        let blame_idx = search_range
-            .iter()
-            .position(|&name| blame_re.is_match(name));
-
-        if let Some(blame_idx) = blame_idx {
-            // Check for exclusions if an exclude pattern is specified
-            let has_exclude = exclude_re
-                .map(|pattern| {
-                    // Only check frames before the blame frame
-                    search_range[..blame_idx]
-                        .iter()
-                        .any(|&name| pattern.is_match(name))
-                })
-                .unwrap_or(false);
+        // Look for the blame frame in the determined search range
+        if let Some(blame_match) = find_blame_match(search_range, blame_re, exclude_re) {
+            let blame_idx = blame_match.blame_idx;
 
             // Count the sample appropriately
-            if has_exclude {
+            if blame_match.excluded {
                 results.excluded_samples += 1;
                 results.excluded_value += value;
                 let method_name = stack[blame_idx].to_string();
@@ -295,6 +724,7 @@ fn analyze_profile(
                 let entry = results.blamed_frames.entry(method_name).or_insert((0, 0));
                 entry.0 += 1;
                 entry.1 += value;
+                results.blamed_samples_raw.push(sample.clone());
             }
         }
     }
@@ -302,18 +732,466 @@ fn analyze_profile(
     results
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opt = Opt::from_args();
+/// Prints collapsed-stack ("folded") output for the samples that pass the
+/// blame/parent/exclude predicate, one line per unique stack in Brendan
+/// Gregg's format: `root;...;leaf value`. Intended to be piped into
+/// `flamegraph.pl`/`inferno`.
+fn folded_output(
+    profile: &Profile,
+    blame_re: &Regex,
+    parent_re: Option<&Regex>,
+    exclude_re: Option<&Regex>,
+    value_index: usize,
+    label_filters: &[(String, String)],
+) {
+    let string_table = StringTable::new(&profile.string_table);
+    let (function_map, location_map) = build_frame_maps(profile, &string_table);
+
+    let mut folded: HashMap<String, (usize, i64)> = HashMap::new();
+
+    for sample in &profile.sample {
+        if !label_filters.is_empty() && !sample_matches_labels(sample, label_filters, &string_table) {
+            continue;
+        }
+
+        let stack = extract_stack(sample, &location_map, &function_map);
+
+        if stack.is_empty() {
+            continue;
+        }
+
+        let value = sample.value.get(value_index).copied().unwrap_or(0);
+
+        let parent_idx =
+            parent_re.and_then(|pattern| stack.iter().position(|&name| pattern.is_match(name)));
+
+        if parent_re.is_some() && parent_idx.is_none() {
+            continue;
+        }
+
+        let search_range = if let Some(p_idx) = parent_idx {
+            &stack[..p_idx]
+        } else {
+            &stack[..]
+        };
+
+        match find_blame_match(search_range, blame_re, exclude_re) {
+            Some(m) if !m.excluded => {}
+            _ => continue,
+        }
+
+        let folded_stack = stack.iter().rev().cloned().collect::<Vec<_>>().join(";");
+        let entry = folded.entry(folded_stack).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += value;
+    }
+
+    let mut lines: Vec<_> = folded.into_iter().collect();
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (stack, (_count, value)) in lines {
+        println!("{} {}", stack, value);
+    }
+}
+
+/// Percentage change of `target_value` relative to `baseline_value`.
+fn percent_change(baseline_value: i64, target_value: i64) -> f64 {
+    if baseline_value != 0 {
+        ((target_value - baseline_value) as f64 / baseline_value as f64) * 100.0
+    } else if target_value != 0 {
+        100.0
+    } else {
+        0.0
+    }
+}
+
+/// Renders a delta as `+X ms (+12.34%)` / `-X ms (-12.34%)`.
+fn format_signed_delta(delta: i64, pct: f64, sample_type: &SampleTypeInfo) -> String {
+    let sign = if delta >= 0 { "+" } else { "-" };
+    format!(
+        "{}{} ({:+.2}%)",
+        sign,
+        sample_type.format_value(delta.abs()),
+        pct
+    )
+}
+
+/// Reports per-blamed-frame deltas between `target` and `baseline`, sorted
+/// by magnitude of change, plus a headline total delta.
+fn print_diff(target: &AnalysisResults, baseline: &AnalysisResults, sample_type: &SampleTypeInfo) {
+    let total_delta = target.blamed_value - baseline.blamed_value;
+    let total_pct = percent_change(baseline.blamed_value, target.blamed_value);
+
+    println!(
+        "Blamed value: {} (target) vs {} (baseline): {}",
+        sample_type.format_value(target.blamed_value),
+        sample_type.format_value(baseline.blamed_value),
+        format_signed_delta(total_delta, total_pct, sample_type)
+    );
+
+    let mut frames: Vec<&String> = target
+        .blamed_frames
+        .keys()
+        .chain(baseline.blamed_frames.keys())
+        .collect();
+    frames.sort();
+    frames.dedup();
+
+    let mut rows: Vec<(&str, i64, f64)> = frames
+        .into_iter()
+        .map(|frame| {
+            let target_value = target.blamed_frames.get(frame).map(|&(_, v)| v).unwrap_or(0);
+            let baseline_value = baseline
+                .blamed_frames
+                .get(frame)
+                .map(|&(_, v)| v)
+                .unwrap_or(0);
+            let delta = target_value - baseline_value;
+            (
+                frame.as_str(),
+                delta,
+                percent_change(baseline_value, target_value),
+            )
+        })
+        .collect();
+
+    rows.sort_by_key(|&(_, delta, _)| -delta.abs());
+
+    println!("\nFrame deltas:");
+    for (frame, delta, pct) in rows {
+        println!("{}: {}", frame, format_signed_delta(delta, pct, sample_type));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_blame(
+    file: PathBuf,
+    blame: String,
+    parent: Option<String>,
+    exclude: Option<String>,
+    sample_type: Option<String>,
+    output: Option<PathBuf>,
+    folded: bool,
+    baseline: Option<PathBuf>,
+    labels: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if folded && output.is_some() {
+        return Err("--folded and --output are mutually exclusive: --folded prints collapsed stacks instead of running the blame report that --output slices".into());
+    }
+
+    if baseline.is_some() && output.is_some() {
+        return Err("--baseline and --output are mutually exclusive: --baseline prints a diff report instead of running the blame report that --output slices".into());
+    }
+
+    if folded && baseline.is_some() {
+        return Err("--folded and --baseline are mutually exclusive: --folded prints collapsed stacks instead of the diff report --baseline produces".into());
+    }
+
+    let blame_re = Regex::new(&blame)?;
+    let parent_re = parent.as_ref().map(|s| Regex::new(s).unwrap());
+    let exclude_re = exclude.as_ref().map(|s| Regex::new(s).unwrap());
+
+    let profile = load_profile(&file)?;
+    let label_filters = parse_label_filters(&labels)?;
+
+    let string_table = StringTable::new(&profile.string_table);
+    let (value_index, sample_type_info) =
+        resolve_sample_type(&profile, &string_table, sample_type.as_deref())?;
+
+    if folded {
+        folded_output(
+            &profile,
+            &blame_re,
+            parent_re.as_ref(),
+            exclude_re.as_ref(),
+            value_index,
+            &label_filters,
+        );
+        return Ok(());
+    }
+
+    let results = analyze_profile(
+        &profile,
+        &blame_re,
+        parent_re.as_ref(),
+        exclude_re.as_ref(),
+        value_index,
+        &label_filters,
+    );
+
+    if let Some(baseline_path) = &baseline {
+        let baseline_profile = load_profile(baseline_path)?;
+        let baseline_string_table = StringTable::new(&baseline_profile.string_table);
+        let (baseline_value_index, _) = resolve_sample_type(
+            &baseline_profile,
+            &baseline_string_table,
+            sample_type.as_deref(),
+        )?;
+
+        let baseline_results = analyze_profile(
+            &baseline_profile,
+            &blame_re,
+            parent_re.as_ref(),
+            exclude_re.as_ref(),
+            baseline_value_index,
+            &label_filters,
+        );
+
+        print_diff(&results, &baseline_results, &sample_type_info);
+        return Ok(());
+    }
+
+    results.print_summary(parent_re.is_some(), &sample_type_info);
+
+    if let Some(output_path) = &output {
+        let filtered = build_filtered_profile(&profile, &results.blamed_samples_raw);
+        write_filtered_profile(output_path, &filtered)?;
+        println!(
+            "\nWrote {} blamed samples to {:?}",
+            filtered.sample.len(),
+            output_path
+        );
+    }
+
+    Ok(())
+}
+
+/// Aggregates each frame's value over the whole profile (self or cumulative)
+/// and prints the `limit` heaviest functions.
+fn run_top(
+    file: PathBuf,
+    sample_type: Option<String>,
+    limit: usize,
+    cumulative: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let profile = load_profile(&file)?;
+
+    let string_table = StringTable::new(&profile.string_table);
+    let (value_index, sample_type_info) =
+        resolve_sample_type(&profile, &string_table, sample_type.as_deref())?;
+
+    let (function_map, location_map) = build_frame_maps(&profile, &string_table);
+
+    let mut totals: HashMap<String, (usize, i64)> = HashMap::new();
+
+    for sample in &profile.sample {
+        let stack = extract_stack(sample, &location_map, &function_map);
 
-    let blame_re = Regex::new(&opt.blame)?;
-    let parent_re = opt.parent.as_ref().map(|s| Regex::new(s).unwrap());
-    let exclude_re = opt.exclude.as_ref().map(|s| Regex::new(s).unwrap());
+        if stack.is_empty() {
+            continue;
+        }
+
+        let value = sample.value.get(value_index).copied().unwrap_or(0);
+
+        if cumulative {
+            let mut seen = HashSet::new();
+            for &name in &stack {
+                if seen.insert(name) {
+                    let entry = totals.entry(name.to_string()).or_insert((0, 0));
+                    entry.0 += 1;
+                    entry.1 += value;
+                }
+            }
+        } else if let Some(&leaf) = stack.first() {
+            // The first frame in the stack is the one actually executing
+            // when the sample was taken (see `folded_output`'s `.rev()`).
+            let entry = totals.entry(leaf.to_string()).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += value;
+        }
+    }
 
-    let profile = load_profile(&opt.file)?;
+    let mut rows: Vec<(String, usize, i64)> = totals
+        .into_iter()
+        .map(|(name, (count, value))| (name, count, value))
+        .collect();
+    rows.sort_by_key(|&(_, _, value)| -value);
 
-    let results = analyze_profile(&profile, &blame_re, parent_re.as_ref(), exclude_re.as_ref());
+    println!(
+        "Top {} functions by {} {} value:\n",
+        limit.min(rows.len()),
+        if cumulative { "cumulative" } else { "self" },
+        sample_type_info.name
+    );
 
-    results.print_summary(parent_re.is_some());
+    for (name, count, value) in rows.into_iter().take(limit) {
+        println!(
+            "{}: {} samples, {}",
+            name,
+            count,
+            sample_type_info.format_value(value)
+        );
+    }
 
     Ok(())
 }
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    match Opt::from_args() {
+        Opt::Blame {
+            file,
+            blame,
+            parent,
+            exclude,
+            sample_type,
+            output,
+            folded,
+            baseline,
+            labels,
+        } => run_blame(
+            file, blame, parent, exclude, sample_type, output, folded, baseline, labels,
+        ),
+        Opt::Top {
+            file,
+            sample_type,
+            limit,
+            cumulative,
+        } => run_top(file, sample_type, limit, cumulative),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tiny profile with three functions/locations, only two of which are
+    /// reachable from the one sample we're going to keep; the third
+    /// ("bar") exists purely to verify it gets garbage-collected.
+    fn sample_profile() -> Profile {
+        Profile {
+            sample_type: vec![ValueType { r#type: 4, unit: 5 }],
+            sample: vec![
+                Sample {
+                    location_id: vec![1, 2],
+                    value: vec![10],
+                    label: vec![],
+                },
+                Sample {
+                    location_id: vec![3],
+                    value: vec![5],
+                    label: vec![],
+                },
+            ],
+            mapping: vec![],
+            location: vec![
+                Location {
+                    id: 1,
+                    mapping_id: 0,
+                    address: 0,
+                    line: vec![profile::Line {
+                        function_id: 1,
+                        line: 0,
+                    }],
+                    is_folded: false,
+                },
+                Location {
+                    id: 2,
+                    mapping_id: 0,
+                    address: 0,
+                    line: vec![profile::Line {
+                        function_id: 2,
+                        line: 0,
+                    }],
+                    is_folded: false,
+                },
+                Location {
+                    id: 3,
+                    mapping_id: 0,
+                    address: 0,
+                    line: vec![profile::Line {
+                        function_id: 3,
+                        line: 0,
+                    }],
+                    is_folded: false,
+                },
+            ],
+            function: vec![
+                Function {
+                    id: 1,
+                    name: 1,
+                    system_name: 1,
+                    filename: 0,
+                    start_line: 0,
+                },
+                Function {
+                    id: 2,
+                    name: 2,
+                    system_name: 2,
+                    filename: 0,
+                    start_line: 0,
+                },
+                Function {
+                    id: 3,
+                    name: 3,
+                    system_name: 3,
+                    filename: 0,
+                    start_line: 0,
+                },
+            ],
+            string_table: vec![
+                "".to_string(),
+                "main".to_string(),
+                "foo".to_string(),
+                "bar".to_string(),
+                "samples".to_string(),
+                "count".to_string(),
+            ],
+            drop_frames: 0,
+            keep_frames: 0,
+            time_nanos: 0,
+            duration_nanos: 0,
+            period_type: String::new(),
+            period: 0,
+            comment: 0,
+            default_sample_type: 0,
+        }
+    }
+
+    #[test]
+    fn build_filtered_profile_renumbers_and_gcs_unreferenced_entries() {
+        let original = sample_profile();
+        let kept_samples = vec![original.sample[0].clone()];
+
+        let filtered = build_filtered_profile(&original, &kept_samples);
+
+        // Only "main" and "foo" are reachable from the kept sample; "bar"
+        // must be garbage-collected out of both function and location.
+        assert_eq!(filtered.function.len(), 2);
+        assert_eq!(filtered.location.len(), 2);
+        assert_eq!(filtered.sample.len(), 1);
+
+        let string_table = StringTable::new(&filtered.string_table);
+        let function_by_id: HashMap<u64, &Function> =
+            filtered.function.iter().map(|f| (f.id, f)).collect();
+        let location_by_id: HashMap<u64, &Location> =
+            filtered.location.iter().map(|l| (l.id, l)).collect();
+
+        let mut seen_names = Vec::new();
+
+        for sample in &filtered.sample {
+            for &loc_id in &sample.location_id {
+                let location = location_by_id
+                    .get(&loc_id)
+                    .unwrap_or_else(|| panic!("sample references missing location {}", loc_id));
+
+                for line in &location.line {
+                    let function = function_by_id.get(&line.function_id).unwrap_or_else(|| {
+                        panic!("location references missing function {}", line.function_id)
+                    });
+
+                    seen_names.push(string_table.get(function.name).to_string());
+                }
+            }
+        }
+
+        seen_names.sort();
+        assert_eq!(seen_names, vec!["foo".to_string(), "main".to_string()]);
+        assert!(!filtered.string_table.contains(&"bar".to_string()));
+
+        // The value-column descriptors must still resolve after the string
+        // table was rebuilt.
+        let value_type = &filtered.sample_type[0];
+        assert_eq!(string_table.get(value_type.r#type), "samples");
+        assert_eq!(string_table.get(value_type.unit), "count");
+    }
+}
@@ -63,14 +63,23 @@ pub struct Sample {
     #[prost(int64, repeated, tag = "2")]
     pub value: ::prost::alloc::vec::Vec<i64>,
 
-    #[prost(int64, repeated, tag = "3")]
-    pub label: ::prost::alloc::vec::Vec<i64>,
+    #[prost(message, repeated, tag = "3")]
+    pub label: ::prost::alloc::vec::Vec<Label>,
+}
 
-    #[prost(int64, repeated, tag = "4")]
-    pub num_label: ::prost::alloc::vec::Vec<i64>,
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Label {
+    #[prost(int64, tag = "1")]
+    pub key: i64,
 
-    #[prost(int64, repeated, tag = "5")]
-    pub num_unit: ::prost::alloc::vec::Vec<i64>,
+    #[prost(int64, tag = "2")]
+    pub str: i64,
+
+    #[prost(int64, tag = "3")]
+    pub num: i64,
+
+    #[prost(int64, tag = "4")]
+    pub num_unit: i64,
 }
 
 #[derive(Clone, PartialEq, ::prost::Message)]